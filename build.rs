@@ -0,0 +1,339 @@
+//! Generates `Pdu` enum and body struct definitions from `pdu.pdl`.
+//!
+//! `pdu.pdl` is the single source of truth for the wire protocol: the
+//! `pdu`/`obsolete` version ids and the per-field tags used by the
+//! field-tagged payload codec (see `TaggedPayload` in
+//! `src/server/codec.rs`) all live there instead of being hand-kept in
+//! sync across struct definitions and macro invocations. This script
+//! parses it into the struct bodies, a `pdu! { ... }` invocation and a
+//! `tagged_fields! { ... }` invocation per struct, and writes the
+//! result to `$OUT_DIR/pdu_generated.rs`, which `codec.rs` pulls in
+//! with `include!`.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+struct Field {
+    name: String,
+    ty: String,
+    tag: u64,
+}
+
+struct Struct {
+    name: String,
+    fields: Vec<Field>,
+}
+
+struct PduVariant {
+    name: String,
+    version: u64,
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find("//") {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// A deliberately small, line-oriented parser for `pdu.pdl`. It is not
+/// a general PDL implementation; it understands exactly the grammar
+/// documented at the top of `pdu.pdl`.
+fn parse_schema(source: &str) -> (Vec<PduVariant>, Vec<Struct>) {
+    let mut variants = Vec::new();
+    let mut structs = Vec::new();
+    // `pdu` and `obsolete` share one version namespace: a version
+    // reused across either kind of line would make the reused id's
+    // variant undecodable, silently, so check both together.
+    let mut seen_versions: std::collections::HashMap<u64, String> = std::collections::HashMap::new();
+
+    let bytes = source.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        // Skip whitespace and line comments outside of struct bodies.
+        let rest = &source[pos..];
+        let trimmed = rest.trim_start();
+        pos += rest.len() - trimmed.len();
+        if pos >= bytes.len() {
+            break;
+        }
+        let rest = &source[pos..];
+        if rest.starts_with("//") {
+            let end = rest.find('\n').map(|i| i + 1).unwrap_or(rest.len());
+            pos += end;
+            continue;
+        }
+        if let Some(line_end) = rest.find(';') {
+            if !rest[..line_end].contains('{') {
+                let decl = strip_comment(&rest[..line_end]).trim();
+                let mut words = decl.split_whitespace();
+                match words.next() {
+                    Some("pdu") => {
+                        let name = words.next().expect("pdu name").to_string();
+                        assert_eq!(words.next(), Some("="));
+                        let version: u64 = words
+                            .next()
+                            .expect("pdu version")
+                            .parse()
+                            .expect("pdu version is a u64");
+                        if let Some(prev) = seen_versions.insert(version, name.clone()) {
+                            panic!(
+                                "pdu.pdl: version {} is declared by both {} and {} — \
+                                 versions must never be reused",
+                                version, prev, name
+                            );
+                        }
+                        variants.push(PduVariant { name, version });
+                    }
+                    Some("obsolete") => {
+                        // Reserved; deliberately not emitted as a variant,
+                        // but its version id still must not collide with
+                        // any `pdu` or other `obsolete` line.
+                        let name = words.next().expect("obsolete name").to_string();
+                        assert_eq!(words.next(), Some("="));
+                        let version: u64 = words
+                            .next()
+                            .expect("obsolete version")
+                            .parse()
+                            .expect("obsolete version is a u64");
+                        if let Some(prev) = seen_versions.insert(version, format!("obsolete {}", name)) {
+                            panic!(
+                                "pdu.pdl: version {} is declared by both {} and obsolete {} — \
+                                 versions must never be reused",
+                                version, prev, name
+                            );
+                        }
+                    }
+                    Some(other) => panic!("unexpected declaration: {}", other),
+                    None => {}
+                }
+                pos += line_end + 1;
+                continue;
+            }
+        }
+        if rest.starts_with("struct") {
+            let open = rest.find('{').expect("struct body");
+            let name = rest[("struct".len())..open].trim().to_string();
+            let close = rest.find('}').expect("closing brace for struct body");
+            let body = &rest[open + 1..close];
+            let mut fields = Vec::new();
+            let mut seen_tags: std::collections::HashMap<u64, String> = std::collections::HashMap::new();
+            for field_decl in body.split(';') {
+                let field_decl = strip_comment(field_decl).trim();
+                if field_decl.is_empty() {
+                    continue;
+                }
+                let (name_and_ty, tag) = field_decl.split_once('=').expect("field = tag");
+                let (field_name, ty) = name_and_ty.split_once(':').expect("field: type");
+                let field_name = field_name.trim().to_string();
+                let tag: u64 = tag.trim().parse().expect("field tag is a u64");
+                if let Some(prev) = seen_tags.insert(tag, field_name.clone()) {
+                    panic!(
+                        "pdu.pdl: struct {} has tag {} on both {} and {} — \
+                         tags must never be reused within a struct",
+                        name, tag, prev, field_name
+                    );
+                }
+                fields.push(Field {
+                    name: field_name,
+                    ty: ty.trim().to_string(),
+                    tag,
+                });
+            }
+            structs.push(Struct { name, fields });
+            pos += close + 1;
+            continue;
+        }
+        panic!("unrecognized content at: {:?}", &rest[..rest.len().min(40)]);
+    }
+    (variants, structs)
+}
+
+/// Primitive/stdlib types we know implement `Default` without having
+/// to see their definition.
+fn is_known_default_primitive(ty: &str) -> bool {
+    matches!(
+        ty,
+        "String"
+            | "bool"
+            | "usize"
+            | "u8"
+            | "u16"
+            | "u32"
+            | "u64"
+            | "i8"
+            | "i16"
+            | "i32"
+            | "i64"
+            | "f32"
+            | "f64"
+    )
+}
+
+/// Whether `ty` is known, from the schema alone, to implement
+/// `Default`: a primitive/stdlib scalar, a generated struct already
+/// established as `Default`-safe, or one of `Vec<T>`/`Option<T>`/
+/// `HashMap<K, V>` over such types. Anything else — in particular
+/// types from external crates like `term::CursorPosition`/`term::Line`
+/// or crate-local types like `TabId`, whose `Default` impl this schema
+/// can't see — is treated as unknown rather than assumed safe.
+fn type_is_default_safe(ty: &str, safe_structs: &std::collections::HashSet<String>) -> bool {
+    let ty = ty.trim();
+    if is_known_default_primitive(ty) || safe_structs.contains(ty) {
+        return true;
+    }
+    for wrapper in ["Vec<", "Option<"] {
+        if let Some(inner) = ty.strip_prefix(wrapper).and_then(|s| s.strip_suffix('>')) {
+            return type_is_default_safe(inner, safe_structs);
+        }
+    }
+    if let Some(inner) = ty.strip_prefix("HashMap<").and_then(|s| s.strip_suffix('>')) {
+        if let Some((k, v)) = inner.split_once(',') {
+            return type_is_default_safe(k, safe_structs) && type_is_default_safe(v, safe_structs);
+        }
+    }
+    false
+}
+
+/// The subset of `structs` whose fields are all `Default`-safe per
+/// `type_is_default_safe`, computed to a fixed point since a struct
+/// can nest another generated struct (e.g. `DirtyLine` inside
+/// `GetCoarseTabRenderableDataResponse`).
+fn default_safe_structs(structs: &[Struct]) -> std::collections::HashSet<String> {
+    let mut safe = std::collections::HashSet::new();
+    loop {
+        let mut changed = false;
+        for s in structs {
+            if safe.contains(&s.name) {
+                continue;
+            }
+            if s.fields.iter().all(|f| type_is_default_safe(&f.ty, &safe)) {
+                safe.insert(s.name.clone());
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    safe
+}
+
+fn generate(variants: &[PduVariant], structs: &[Struct]) -> String {
+    let mut out = String::new();
+    out.push_str("// @generated by build.rs from pdu.pdl. Do not edit by hand.\n\n");
+
+    // `decode_tagged` already requires every field type to implement
+    // `Default` (it default-fills any tag missing from the wire), so
+    // that much is unconditional regardless of what we derive here.
+    // Deriving `Default` on the struct itself is free on top of that
+    // *if* every field is known-`Default`-safe from the schema alone;
+    // skip it (and the conformance tests that call `::default()`
+    // directly below) for structs that nest an external type we can't
+    // see the definition of, rather than assuming it compiles.
+    let safe_structs = default_safe_structs(structs);
+
+    for s in structs {
+        let derives = if safe_structs.contains(&s.name) {
+            "#[derive(Deserialize, Serialize, PartialEq, Debug, Default)]\n"
+        } else {
+            "#[derive(Deserialize, Serialize, PartialEq, Debug)]\n"
+        };
+        out.push_str(derives);
+        out.push_str(&format!("pub struct {} {{\n", s.name));
+        for f in &s.fields {
+            out.push_str(&format!("    pub {}: {},\n", f.name, f.ty));
+        }
+        out.push_str("}\n\n");
+
+        out.push_str(&format!("tagged_fields!({} {{\n", s.name));
+        for f in &s.fields {
+            out.push_str(&format!("    {}: {} = {},\n", f.name, f.ty, f.tag));
+        }
+        out.push_str("});\n\n");
+    }
+
+    out.push_str("pdu! {\n");
+    for v in variants {
+        out.push_str(&format!("    {}: {},\n", v.name, v.version));
+    }
+    out.push_str("}\n\n");
+
+    out.push_str(&generate_conformance_tests(variants, structs, &safe_structs));
+
+    out
+}
+
+/// Emits one round-trip test per schema struct (`TaggedPayload`) and
+/// one per `Pdu` variant (the full frame encode/decode path), so that
+/// every PDU declared in `pdu.pdl` gets conformance coverage without
+/// anyone having to remember to hand-write it. Skipped for structs (and
+/// the variants backed by them) that aren't in `safe_structs`, since
+/// the test needs `::default()` on the struct itself.
+fn generate_conformance_tests(
+    variants: &[PduVariant],
+    structs: &[Struct],
+    safe_structs: &std::collections::HashSet<String>,
+) -> String {
+    let mut out = String::new();
+    out.push_str("#[cfg(test)]\n");
+    out.push_str("mod generated_conformance_tests {\n");
+    out.push_str("    use super::*;\n\n");
+
+    for s in structs {
+        if !safe_structs.contains(&s.name) {
+            out.push_str(&format!(
+                "    // {name} isn't Default-safe (see `default_safe_structs` in \
+                 build.rs) — no generated round-trip test.\n\n",
+                name = s.name
+            ));
+            continue;
+        }
+        out.push_str(&format!(
+            "    #[test]\n    fn test_tagged_roundtrip_{name}() {{\n        \
+             let value = {name}::default();\n        \
+             let encoded = value.encode_tagged().unwrap();\n        \
+             assert_eq!({name}::decode_tagged(&encoded).unwrap(), value);\n    }}\n\n",
+            name = s.name
+        ));
+    }
+
+    for v in variants {
+        if !safe_structs.contains(&v.name) {
+            out.push_str(&format!(
+                "    // {name} isn't Default-safe (see `default_safe_structs` in \
+                 build.rs) — no generated round-trip test.\n\n",
+                name = v.name
+            ));
+            continue;
+        }
+        out.push_str(&format!(
+            "    #[test]\n    fn test_pdu_roundtrip_{name}() {{\n        \
+             let pdu = Pdu::{name}({name}::default());\n        \
+             let mut encoded = Vec::new();\n        \
+             pdu.encode(&mut encoded, 1).unwrap();\n        \
+             let decoded = Pdu::decode(encoded.as_slice()).unwrap();\n        \
+             assert_eq!(decoded, DecodedPdu {{ serial: 1, pdu }});\n    }}\n\n",
+            name = v.name
+        ));
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let schema_path = Path::new(&manifest_dir).join("pdu.pdl");
+    println!("cargo:rerun-if-changed={}", schema_path.display());
+
+    let source = fs::read_to_string(&schema_path)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", schema_path.display(), err));
+    let (variants, structs) = parse_schema(&source);
+    let generated = generate(&variants, &structs);
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let dest = Path::new(&out_dir).join("pdu_generated.rs");
+    fs::write(&dest, generated)
+        .unwrap_or_else(|err| panic!("failed to write {}: {}", dest.display(), err));
+}